@@ -1,6 +1,8 @@
+use std::io::Read;
 use std::path::PathBuf;
+use std::process::{Child, Stdio};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use chrono::{DateTime, Local, Utc};
@@ -8,7 +10,7 @@ use clap::Args;
 use prettytable::{format, row, Table};
 
 use crate::{
-    schema::{self, JobBuilder},
+    schema::{self, FailureKind, JobBuilder, Schedule, ID},
     JobManager,
 };
 
@@ -17,35 +19,52 @@ pub struct List {}
 
 impl List {
     pub fn run(&self, job_manager: JobManager) -> anyhow::Result<()> {
-        let jobs = job_manager.get_all_jobs()?;
+        let metas = job_manager.get_all_metas()?;
 
         let mut table = Table::new();
         table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
 
-        table.set_titles(row!["ID", "Name", "State", "Script", "Run At"]);
-        for job in jobs {
-            let state = match job.state {
-                schema::JobState::Done => {
-                    let result = job_manager.get_result(&job)?;
-                    assert!(result.is_some(), "job state is Done but result is None");
-
-                    let result = result.unwrap();
-                    assert!(
-                        result.status.is_some(),
-                        "job state is Done but result status is None"
-                    );
-
-                    let status = result.status.unwrap();
-                    format!("({})", status)
+        table.set_titles(row!["ID", "Name", "Schedule", "Script", "State", "Run At"]);
+        for meta in metas {
+            let latest = job_manager.get_latest_run(meta.id)?;
+
+            let schedule = match meta.schedule {
+                Schedule::Once(_) => "once".to_string(),
+                Schedule::Recurring(interval) => {
+                    format!("every {}", humantime::format_duration(interval))
+                }
+            };
+
+            let (state, run_at) = match &latest {
+                Some(run) => {
+                    let state = match run.state {
+                        schema::JobState::Done => {
+                            let result = job_manager.get_result(run)?;
+                            assert!(result.is_some(), "run state is Done but result is None");
+
+                            let result = result.unwrap();
+                            assert!(
+                                result.status.is_some(),
+                                "run state is Done but result status is None"
+                            );
+
+                            let status = result.status.unwrap();
+                            format!("{}({})", run.state, status)
+                        }
+                        _ => run.state.to_string(),
+                    };
+                    (state, run.run_at.to_string())
                 }
-                _ => "".to_string(),
+                None => ("".to_string(), "".to_string()),
             };
+
             table.add_row(row![
-                job.id,
-                job.name.unwrap_or("".to_string()),
-                format!("{}{}", job.state, state),
-                job.script,
-                job.run_at,
+                meta.id,
+                meta.name.unwrap_or("".to_string()),
+                schedule,
+                meta.script,
+                state,
+                run_at,
             ]);
         }
         table.printstd();
@@ -58,10 +77,29 @@ pub struct Add {
     #[clap(short, long)]
     pub name: Option<String>,
 
+    /// When to run the job (or, with `--every`, when it first runs).
     pub run_at: DateTime<Local>,
     pub script: String,
 
     pub cwd: Option<PathBuf>,
+
+    /// Run the job first at `run_at`, then again every `EVERY` after each
+    /// run finishes, instead of only once.
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub every: Option<Duration>,
+
+    /// How many times to retry the script after a failed attempt.
+    #[clap(long, default_value_t = 0)]
+    pub max_retries: i32,
+
+    /// Base delay before the first retry; later retries back off
+    /// exponentially from this value.
+    #[clap(long, value_parser = humantime::parse_duration, default_value = "0s")]
+    pub retry_delay: Duration,
+
+    /// Kill the script if it hasn't finished within this long.
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub timeout: Option<Duration>,
 }
 
 impl Add {
@@ -71,44 +109,195 @@ impl Add {
         let cwd = self.cwd.clone().unwrap_or(cwd);
 
         let job_builder = JobBuilder::new()
-            .state(schema::JobState::Queued)
             .script(self.script.clone())
-            .run_at(self.run_at.to_utc())
-            .cwd(cwd);
+            .cwd(cwd)
+            .max_retries(self.max_retries)
+            .retry_delay(self.retry_delay);
+        let job_builder = if let Some(timeout) = self.timeout {
+            job_builder.timeout(timeout)
+        } else {
+            job_builder
+        };
         let job_builder = if let Some(name) = &self.name {
             job_builder.name(name.clone())
         } else {
             job_builder
         };
 
-        let job = job_builder.build();
-        let job = job_manager.enqueue(job)?;
-        println!("add job {}", job.id);
+        let run_at = self.run_at.to_utc();
+        let meta = if let Some(every) = self.every {
+            job_builder.recurring(every).build()
+        } else {
+            job_builder.run_at(run_at).build()
+        };
+
+        let meta = job_manager.enqueue(meta, run_at)?;
+        println!("add job {}", meta.id);
         Ok(())
     }
 }
 
 #[derive(Args, Debug)]
-pub struct Delete {
-    pub job_id: i64,
+pub struct Cancel {
+    pub meta_id: i64,
 }
 
-impl Delete {
+impl Cancel {
     pub fn run(&self, job_manager: JobManager) -> anyhow::Result<()> {
         let mut job_manager = job_manager;
+        let meta_id: ID = self.meta_id.into();
 
-        let Some(job) = job_manager
-            .get_job(self.job_id.into())
+        let Some(latest) = job_manager
+            .get_latest_run(meta_id)
             .context("failed to get job")?
         else {
-            eprintln!("job {} not found", self.job_id);
+            eprintln!("job {} not found", self.meta_id);
             std::process::exit(1);
         };
-        let _ = job_manager.delete(&job).context("failed to delete job")?;
+        let Some(mut guard) = job_manager
+            .get_job_mut(latest.id)
+            .context("failed to get job")?
+        else {
+            eprintln!("job {} not found", self.meta_id);
+            std::process::exit(1);
+        };
+        guard.cancel().context("failed to cancel job")?;
         Ok(())
     }
 }
 
+#[derive(Args, Debug)]
+pub struct Delete {
+    pub meta_id: i64,
+}
+
+impl Delete {
+    pub fn run(&self, job_manager: JobManager) -> anyhow::Result<()> {
+        let mut job_manager = job_manager;
+        let meta_id: ID = self.meta_id.into();
+
+        if job_manager
+            .get_meta(meta_id)
+            .context("failed to get job")?
+            .is_none()
+        {
+            eprintln!("job {} not found", self.meta_id);
+            std::process::exit(1);
+        }
+        job_manager
+            .delete(meta_id)
+            .context("failed to delete job")?;
+        Ok(())
+    }
+}
+
+/// Upper bound on the exponential retry backoff, regardless of
+/// `retry_delay` and how many attempts have already been made.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// How long to give a timed-out script to exit after `SIGTERM` before
+/// escalating to `SIGKILL`.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// How often the timeout poll loop checks whether the child has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The delay before the `attempts`-th retry: `retry_delay` doubled once
+/// per prior attempt, capped at [`MAX_RETRY_DELAY`].
+fn compute_backoff(retry_delay: Duration, attempts: i32) -> Duration {
+    retry_delay
+        .saturating_mul(1u32 << attempts.min(30) as u32)
+        .min(MAX_RETRY_DELAY)
+}
+
+/// The result of running a job's script to completion, or killing it
+/// after `timeout` elapsed.
+struct ScriptOutcome {
+    status: Option<i16>,
+    stdout: String,
+    stderr: String,
+    timed_out: bool,
+}
+
+/// Runs `script` under `/bin/sh -c`, killing it (and any children it
+/// spawned, via its process group) if it's still running after
+/// `timeout`.
+fn run_script(
+    script: &str,
+    cwd: &std::path::Path,
+    timeout: Option<Duration>,
+) -> std::io::Result<ScriptOutcome> {
+    let mut command = std::process::Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(script)
+        .current_dir(cwd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    let mut child = command.spawn()?;
+    let mut stdout = child.stdout.take().expect("stdout was piped");
+    let mut stderr = child.stderr.take().expect("stderr was piped");
+    let stdout_thread = thread::spawn(move || {
+        let mut buf = String::new();
+        let _ = stdout.read_to_string(&mut buf);
+        buf
+    });
+    let stderr_thread = thread::spawn(move || {
+        let mut buf = String::new();
+        let _ = stderr.read_to_string(&mut buf);
+        buf
+    });
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            timed_out = true;
+            kill_process_group(&mut child);
+            break child.wait()?;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    Ok(ScriptOutcome {
+        status: status.code().map(|c| c as i16),
+        stdout: stdout_thread.join().unwrap_or_default(),
+        stderr: stderr_thread.join().unwrap_or_default(),
+        timed_out,
+    })
+}
+
+/// Kills `child`'s whole process group, escalating from `SIGTERM` to
+/// `SIGKILL` if it hasn't exited after [`KILL_GRACE_PERIOD`].
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) {
+    let pid = child.id() as i32;
+    unsafe {
+        libc::kill(-pid, libc::SIGTERM);
+    }
+    thread::sleep(KILL_GRACE_PERIOD);
+    if child.try_wait().ok().flatten().is_none() {
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn kill_process_group(child: &mut Child) {
+    // not tested
+    let _ = child.kill();
+}
+
 #[derive(Args, Debug)]
 pub struct Run {
     #[clap(short, long, value_parser = humantime::parse_duration)]
@@ -164,21 +353,63 @@ impl Run {
 
         log::info!("start job:{}", job_id);
         println!("start job:{}", job_id);
-        let output = std::process::Command::new("/bin/sh")
-            .arg("-c")
-            .arg(&job.script)
-            .current_dir(&job.cwd)
-            .output()?;
-
-        let mut job_result = schema::JobResult::new(job.id);
-        job_result.status = output.status.code().map(|c| c as i16);
-        job_result.stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        job_result.stderr = String::from_utf8_lossy(&output.stderr).to_string();
-
-        let _ = job.save_job_result(job_result)?;
-        log::info!("insert job result:{}", job_id);
-        log::info!("update job state to Done:{}", job_id);
-        println!("done job:{}", job_id);
+        let outcome = run_script(&job.script, &job.cwd, job.timeout);
+
+        let (failure, job_result) = match outcome {
+            Err(err) => {
+                let mut job_result = schema::JobResult::new(job.id);
+                job_result.stderr = err.to_string();
+                (Some(FailureKind::Spawn), job_result)
+            }
+            Ok(outcome) => {
+                let mut job_result = schema::JobResult::new(job.id);
+                job_result.status = outcome.status;
+                job_result.stdout = outcome.stdout;
+                job_result.stderr = outcome.stderr;
+
+                let failure = if outcome.timed_out {
+                    Some(FailureKind::Timeout)
+                } else if outcome.status == Some(0) {
+                    None
+                } else if outcome.status.is_none() {
+                    Some(FailureKind::Signal)
+                } else {
+                    Some(FailureKind::NonZeroExit)
+                };
+                (failure, job_result)
+            }
+        };
+
+        let Some(failure) = failure else {
+            let _ = job.save_job_result(job_result)?;
+            job.schedule_next_if_recurring()?;
+            log::info!("insert job result:{}", job_id);
+            log::info!("update job state to Done:{}", job_id);
+            println!("done job:{}", job_id);
+            return Ok(());
+        };
+
+        if job.attempts < job.max_retries {
+            let backoff = compute_backoff(job.retry_delay, job.attempts);
+            let next_run_at = Utc::now() + chrono::Duration::from_std(backoff).unwrap_or_default();
+
+            log::warn!(
+                "job:{} failed ({}), retrying at {} (attempt {}/{})",
+                job_id,
+                failure,
+                next_run_at,
+                job.attempts + 1,
+                job.max_retries
+            );
+            job.retry(next_run_at, failure)?;
+            println!("retry job:{} ({})", job_id, failure);
+        } else {
+            let _ = job.save_job_result(job_result)?;
+            job.schedule_next_if_recurring()?;
+            log::info!("insert job result:{}", job_id);
+            log::info!("update job state to Done:{}", job_id);
+            println!("done job:{} ({})", job_id, failure);
+        }
 
         Ok(())
     }
@@ -186,24 +417,62 @@ impl Run {
 
 #[derive(Args, Debug)]
 pub struct Log {
-    pub job_id: i64,
+    pub meta_id: i64,
 }
 
 impl Log {
     pub fn run(&self, job_manager: JobManager) -> anyhow::Result<()> {
-        let Some(job) = job_manager
-            .get_job(self.job_id.into())
+        let meta_id: ID = self.meta_id.into();
+
+        let Some(meta) = job_manager
+            .get_meta(meta_id)
             .context("failed to get job")?
         else {
-            eprintln!("job {} not found", self.job_id);
+            eprintln!("job {} not found", self.meta_id);
             std::process::exit(1);
         };
 
+        let runs = job_manager
+            .get_runs(meta_id)
+            .context("failed to get job history")?;
+
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row![
+            "Run",
+            "State",
+            "Run At",
+            "Started At",
+            "Attempts",
+            "Last Failure"
+        ]);
+        for run in &runs {
+            table.add_row(row![
+                run.id,
+                run.state,
+                run.run_at,
+                run.started_at
+                    .map(|t| t.to_string())
+                    .unwrap_or("".to_string()),
+                run.attempts,
+                run.last_failure
+                    .map(|f| f.to_string())
+                    .unwrap_or("".to_string()),
+            ]);
+        }
+        table.printstd();
+
+        let Some(latest_done) = runs.iter().rev().find(|run| run.state == schema::JobState::Done)
+        else {
+            eprintln!("job {} has no completed runs yet", meta.id);
+            return Ok(());
+        };
+
         let Some(result) = job_manager
-            .get_result(&job)
+            .get_result(latest_done)
             .context("failed to get job result")?
         else {
-            eprintln!("job {} result not found", job.id);
+            eprintln!("job {} result not found", latest_done.id);
             std::process::exit(1);
         };
         print!("{}", result.stdout);
@@ -212,3 +481,23 @@ impl Log {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute_backoff_doubles_per_attempt() {
+        let retry_delay = Duration::from_secs(1);
+        assert_eq!(compute_backoff(retry_delay, 0), Duration::from_secs(1));
+        assert_eq!(compute_backoff(retry_delay, 1), Duration::from_secs(2));
+        assert_eq!(compute_backoff(retry_delay, 2), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn compute_backoff_caps_at_max_retry_delay() {
+        let retry_delay = Duration::from_secs(1);
+        assert_eq!(compute_backoff(retry_delay, 20), MAX_RETRY_DELAY);
+        assert_eq!(compute_backoff(retry_delay, 30), MAX_RETRY_DELAY);
+    }
+}
@@ -1,25 +1,64 @@
-use rusqlite::{params, Connection};
+use std::time::Duration;
 
-use crate::schema::{Job, JobResult, JobState, ID};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, Row};
+
+use crate::schema::{AssignedJob, FailureKind, JobMeta, JobResult, JobState, Schedule, ID};
 use crate::{bytes_to_path, path_to_bytes, Result};
 
+const ASSIGNED_JOB_COLUMNS: &str = "
+    job_runs.id, job_runs.meta_id, job_meta.name, job_meta.script, job_meta.cwd,
+    job_meta.schedule_kind, job_meta.schedule_once_at, job_meta.schedule_interval,
+    job_meta.max_retries, job_meta.retry_delay, job_meta.timeout_secs,
+    job_runs.state, job_runs.run_at, job_runs.started_at, job_runs.attempts, job_runs.last_failure
+";
+
+const ASSIGNED_JOB_JOIN: &str = "FROM job_runs JOIN job_meta ON job_meta.id = job_runs.meta_id";
+
+/// Puts a freshly-opened connection into a state safe for multiple
+/// `rat run` workers to share the same `rat.db`: WAL journaling so
+/// readers and writers don't block each other, and a busy-timeout so a
+/// writer blocked by another connection retries instead of failing
+/// immediately with `SQLITE_BUSY`.
+pub fn configure_connection(conn: &Connection) -> anyhow::Result<()> {
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.busy_timeout(Duration::from_secs(5))?;
+    Ok(())
+}
+
 pub fn create_table(conn: &Connection) -> anyhow::Result<()> {
     conn.execute(
-        "CREATE TABLE IF NOT EXISTS jobs (
+        "CREATE TABLE IF NOT EXISTS job_meta (
+                  id                 INTEGER PRIMARY KEY,
+                  name               TEXT,
+                  script             TEXT NOT NULL,
+                  cwd                BLOB NOT NULL,
+                  schedule_kind      INTEGER NOT NULL,
+                  schedule_once_at   TEXT,
+                  schedule_interval  INTEGER,
+                  max_retries        INTEGER NOT NULL DEFAULT 0,
+                  retry_delay        INTEGER NOT NULL DEFAULT 0,
+                  timeout_secs       INTEGER
+             )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS job_runs (
                   id              INTEGER PRIMARY KEY,
-                  name            TEXT,
+                  meta_id         INTEGER NOT NULL,
                   state           INTEGER NOT NULL,
-                  script          TEXT NOT NULL,
                   run_at          TEXT NOT NULL,
-                  cwd             BLOB NOT NULL
+                  started_at      TEXT,
+                  attempts        INTEGER NOT NULL DEFAULT 0,
+                  last_failure    INTEGER
              )",
         [],
     )?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS job_results (
                   id              INTEGER PRIMARY KEY,
-                  job_id          INTEGER NOT NULL,
-                  status          INTEGER NOT NULL,
+                  run_id          INTEGER NOT NULL,
+                  status          INTEGER,
                   stdout          TEXT NOT NULL,
                   stderr          TEXT NOT NULL
              )",
@@ -28,21 +67,89 @@ pub fn create_table(conn: &Connection) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn select_queued_jobs(conn: &Connection) -> Result<Vec<Job>> {
-    select_jobs(conn, Some(JobState::Queued))
+fn row_to_assigned_job(row: &Row) -> rusqlite::Result<AssignedJob> {
+    let id: i64 = row.get(0)?;
+    let meta_id: i64 = row.get(1)?;
+    let retry_delay_secs: i64 = row.get(9)?;
+    let timeout_secs: Option<i64> = row.get(10)?;
+    Ok(AssignedJob {
+        id: id.into(),
+        meta_id: meta_id.into(),
+        name: row.get(2)?,
+        script: row.get(3)?,
+        cwd: bytes_to_path(row.get::<_, Vec<u8>>(4)?),
+        schedule: row_to_schedule(row, 5, 6, 7)?,
+        max_retries: row.get(8)?,
+        retry_delay: Duration::from_secs(retry_delay_secs as u64),
+        timeout: timeout_secs.map(|secs| Duration::from_secs(secs as u64)),
+        state: row.get(11)?,
+        run_at: row.get(12)?,
+        started_at: row.get(13)?,
+        attempts: row.get(14)?,
+        last_failure: row.get(15)?,
+    })
 }
 
-pub fn insert_job(conn: &mut Connection, job: &Job) -> Result<ID> {
-    let tx = conn.transaction()?;
+fn row_to_schedule(
+    row: &Row,
+    kind_idx: usize,
+    once_at_idx: usize,
+    interval_idx: usize,
+) -> rusqlite::Result<Schedule> {
+    let kind: i64 = row.get(kind_idx)?;
+    match kind {
+        0 => Ok(Schedule::Once(row.get(once_at_idx)?)),
+        1 => {
+            let interval_secs: i64 = row.get(interval_idx)?;
+            Ok(Schedule::Recurring(Duration::from_secs(
+                interval_secs as u64,
+            )))
+        }
+        _ => Err(rusqlite::Error::InvalidColumnType(
+            kind_idx,
+            "schedule_kind".to_string(),
+            rusqlite::types::Type::Integer,
+        )),
+    }
+}
 
+fn row_to_meta(row: &Row) -> rusqlite::Result<JobMeta> {
+    let id: i64 = row.get(0)?;
+    let retry_delay_secs: i64 = row.get(8)?;
+    let timeout_secs: Option<i64> = row.get(9)?;
+    Ok(JobMeta {
+        id: id.into(),
+        name: row.get(1)?,
+        script: row.get(2)?,
+        cwd: bytes_to_path(row.get::<_, Vec<u8>>(3)?),
+        schedule: row_to_schedule(row, 4, 5, 6)?,
+        max_retries: row.get(7)?,
+        retry_delay: Duration::from_secs(retry_delay_secs as u64),
+        timeout: timeout_secs.map(|secs| Duration::from_secs(secs as u64)),
+    })
+}
+
+pub fn insert_meta(conn: &mut Connection, meta: &JobMeta) -> Result<ID> {
+    let (schedule_kind, schedule_once_at, schedule_interval): (i64, Option<DateTime<Utc>>, Option<i64>) =
+        match meta.schedule {
+            Schedule::Once(at) => (0, Some(at), None),
+            Schedule::Recurring(interval) => (1, None, Some(interval.as_secs() as i64)),
+        };
+
+    let tx = conn.transaction()?;
     tx.execute(
-        "INSERT INTO jobs (name, state, script, run_at, cwd) VALUES (?1, ?2, ?3, ?4, ?5)",
+        "INSERT INTO job_meta (name, script, cwd, schedule_kind, schedule_once_at, schedule_interval, max_retries, retry_delay, timeout_secs)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
         params![
-            job.name,
-            job.state,
-            job.script,
-            job.run_at,
-            path_to_bytes(&job.cwd)
+            meta.name,
+            meta.script,
+            path_to_bytes(&meta.cwd),
+            schedule_kind,
+            schedule_once_at,
+            schedule_interval,
+            meta.max_retries,
+            meta.retry_delay.as_secs() as i64,
+            meta.timeout.map(|t| t.as_secs() as i64),
         ],
     )?;
     let id = tx.last_insert_rowid();
@@ -51,21 +158,11 @@ pub fn insert_job(conn: &mut Connection, job: &Job) -> Result<ID> {
     Ok(id.into())
 }
 
-pub fn insert_job_result(conn: &mut Connection, job_result: &JobResult) -> Result<ID> {
+pub fn insert_run(conn: &mut Connection, meta_id: ID, run_at: DateTime<Utc>) -> Result<ID> {
     let tx = conn.transaction()?;
-
     tx.execute(
-        "UPDATE jobs SET state = ?1 WHERE id = ?2",
-        params![JobState::Done, job_result.job_id],
-    )?;
-    tx.execute(
-        "INSERT INTO job_results (job_id, status, stdout, stderr) VALUES (?1, ?2, ?3, ?4)",
-        params![
-            job_result.job_id,
-            job_result.status,
-            job_result.stdout,
-            job_result.stderr
-        ],
+        "INSERT INTO job_runs (meta_id, state, run_at) VALUES (?1, ?2, ?3)",
+        params![meta_id, JobState::Queued, run_at],
     )?;
     let id = tx.last_insert_rowid();
     tx.commit()?;
@@ -73,86 +170,188 @@ pub fn insert_job_result(conn: &mut Connection, job_result: &JobResult) -> Resul
     Ok(id.into())
 }
 
-pub fn select_job(conn: &Connection, job_id: ID) -> Result<Option<Job>> {
-    let mut stmt =
-        conn.prepare("SELECT id,name,state,script,run_at,cwd FROM jobs WHERE id = ?1")?;
-    let job = stmt
-        .query_map(params![job_id], |row| {
-            let id: i64 = row.get(0)?;
-            Ok(Job {
-                id: id.into(),
-                name: row.get(1)?,
-                state: row.get(2)?,
-                script: row.get(3)?,
-                run_at: row.get(4)?,
-                cwd: bytes_to_path(row.get::<_, Vec<u8>>(5)?),
-            })
+/// Atomically claims the next queued run whose `run_at` has passed and
+/// marks it `Dequeued` in a single statement, so two workers racing
+/// against the same `rat.db` can never both pick up the same row.
+pub fn dequeue_job(conn: &Connection) -> Result<Option<AssignedJob>> {
+    let mut stmt = conn.prepare(
+        "UPDATE job_runs SET state = ?1 WHERE id = (
+            SELECT id FROM job_runs WHERE state = ?2 AND run_at <= ?3 ORDER BY run_at LIMIT 1
+         ) RETURNING id",
+    )?;
+    let run_id: Option<i64> = stmt
+        .query_map(params![JobState::Dequeued, JobState::Queued, Utc::now()], |row| {
+            row.get(0)
         })?
         .next()
         .transpose()?;
+    let Some(run_id) = run_id else {
+        return Ok(None);
+    };
+    select_job(conn, run_id.into())
+}
+
+pub fn select_job(conn: &Connection, run_id: ID) -> Result<Option<AssignedJob>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {ASSIGNED_JOB_COLUMNS} {ASSIGNED_JOB_JOIN} WHERE job_runs.id = ?1"
+    ))?;
+    let job = stmt
+        .query_map(params![run_id], row_to_assigned_job)?
+        .next()
+        .transpose()?;
+    Ok(job)
+}
+
+pub fn select_runs_for_meta(conn: &Connection, meta_id: ID) -> Result<Vec<AssignedJob>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {ASSIGNED_JOB_COLUMNS} {ASSIGNED_JOB_JOIN} WHERE job_runs.meta_id = ?1 ORDER BY job_runs.run_at"
+    ))?;
+    let runs = stmt.query_map(params![meta_id], row_to_assigned_job)?;
+    let mut result = Vec::new();
+    for run in runs {
+        result.push(run?);
+    }
+    Ok(result)
+}
+
+/// The most recently scheduled run for `meta_id`, used by `List` to show
+/// each job's current status next to its definition.
+pub fn select_latest_run_for_meta(conn: &Connection, meta_id: ID) -> Result<Option<AssignedJob>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {ASSIGNED_JOB_COLUMNS} {ASSIGNED_JOB_JOIN} WHERE job_runs.meta_id = ?1 ORDER BY job_runs.run_at DESC LIMIT 1"
+    ))?;
+    let job = stmt
+        .query_map(params![meta_id], row_to_assigned_job)?
+        .next()
+        .transpose()?;
     Ok(job)
 }
 
-pub fn update_job_state(conn: &mut Connection, job: &Job, state: JobState) -> Result<()> {
+pub fn select_meta(conn: &Connection, meta_id: ID) -> Result<Option<JobMeta>> {
+    let mut stmt = conn.prepare(
+        "SELECT id,name,script,cwd,schedule_kind,schedule_once_at,schedule_interval,max_retries,retry_delay,timeout_secs
+         FROM job_meta WHERE id = ?1",
+    )?;
+    let meta = stmt
+        .query_map(params![meta_id], row_to_meta)?
+        .next()
+        .transpose()?;
+    Ok(meta)
+}
+
+pub fn select_all_metas(conn: &Connection) -> Result<Vec<JobMeta>> {
+    let mut stmt = conn.prepare(
+        "SELECT id,name,script,cwd,schedule_kind,schedule_once_at,schedule_interval,max_retries,retry_delay,timeout_secs
+         FROM job_meta",
+    )?;
+    let metas = stmt.query_map([], row_to_meta)?;
+    let mut result = Vec::new();
+    for meta in metas {
+        result.push(meta?);
+    }
+    Ok(result)
+}
+
+/// Updates a run's state, optionally only when it is still in
+/// `cond_state` (used by `JobGuard`'s drop handler to give up a run back
+/// to the queue without clobbering a state another worker already moved
+/// it to).
+pub fn update_job_state(
+    conn: &mut Connection,
+    job: &AssignedJob,
+    state: JobState,
+    cond_state: Option<JobState>,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    match cond_state {
+        Some(cond_state) => {
+            tx.execute(
+                "UPDATE job_runs SET state = ?1 WHERE id = ?2 AND state = ?3",
+                params![state, job.id, cond_state],
+            )?;
+        }
+        None => {
+            tx.execute(
+                "UPDATE job_runs SET state = ?1 WHERE id = ?2",
+                params![state, job.id],
+            )?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn mark_running(conn: &mut Connection, job: &AssignedJob) -> Result<()> {
     let tx = conn.transaction()?;
     tx.execute(
-        "UPDATE jobs SET state = ?1 WHERE id = ?2",
-        params![state, job.id],
+        "UPDATE job_runs SET state = ?1, started_at = ?2 WHERE id = ?3",
+        params![JobState::Running, Utc::now(), job.id],
     )?;
     tx.commit()?;
     Ok(())
 }
 
-pub fn delete_job(conn: &mut Connection, job: &Job) -> Result<()> {
+/// Schedules a failed run for another attempt: bumps `attempts`, records
+/// why it failed, and moves `run_at` out to `next_run_at` while putting
+/// the run back in the `Queued` state.
+pub fn retry_job(
+    conn: &mut Connection,
+    job: &AssignedJob,
+    next_run_at: DateTime<Utc>,
+    failure: FailureKind,
+) -> Result<()> {
     let tx = conn.transaction()?;
-    tx.execute("DELETE FROM job_results WHERE job_id = ?1", params![job.id])?;
-    tx.execute("DELETE FROM jobs WHERE id = ?1", params![job.id])?;
+    tx.execute(
+        "UPDATE job_runs SET state = ?1, run_at = ?2, attempts = attempts + 1, last_failure = ?3 WHERE id = ?4",
+        params![JobState::Queued, next_run_at, failure, job.id],
+    )?;
     tx.commit()?;
-
     Ok(())
 }
 
-pub fn select_all_jobs(conn: &Connection) -> Result<Vec<Job>> {
-    select_jobs(conn, None)
+pub fn delete_meta(conn: &mut Connection, meta_id: ID) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM job_results WHERE run_id IN (SELECT id FROM job_runs WHERE meta_id = ?1)",
+        params![meta_id],
+    )?;
+    tx.execute("DELETE FROM job_runs WHERE meta_id = ?1", params![meta_id])?;
+    tx.execute("DELETE FROM job_meta WHERE id = ?1", params![meta_id])?;
+    tx.commit()?;
+
+    Ok(())
 }
 
-fn select_jobs(conn: &Connection, state: Option<JobState>) -> Result<Vec<Job>> {
-    let (mut stmt, params) = match state {
-        Some(state) => (
-            conn.prepare("SELECT id,name,state,script,run_at,cwd FROM jobs WHERE state = ?1")?,
-            params![state.clone()],
-        ),
-        None => (
-            conn.prepare("SELECT id,name,state,script,run_at,cwd FROM jobs")?,
-            params![],
-        ),
-    };
-    let jobs = stmt.query_map(params, |row| {
-        let id: i64 = row.get(0)?;
-        Ok(Job {
-            id: id.into(),
-            name: row.get(1)?,
-            state: row.get(2)?,
-            script: row.get(3)?,
-            run_at: row.get(4)?,
-            cwd: bytes_to_path(row.get::<_, Vec<u8>>(5)?),
-        })
-    })?;
-    let mut result = Vec::new();
-    for job in jobs {
-        result.push(job?);
-    }
-    Ok(result)
+pub fn insert_job_result(conn: &mut Connection, job_result: &JobResult) -> Result<ID> {
+    let tx = conn.transaction()?;
+
+    tx.execute(
+        "UPDATE job_runs SET state = ?1 WHERE id = ?2",
+        params![JobState::Done, job_result.run_id],
+    )?;
+    tx.execute(
+        "INSERT INTO job_results (run_id, status, stdout, stderr) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            job_result.run_id,
+            job_result.status,
+            job_result.stdout,
+            job_result.stderr
+        ],
+    )?;
+    let id = tx.last_insert_rowid();
+    tx.commit()?;
+
+    Ok(id.into())
 }
 
-pub fn get_job_result(conn: &Connection, job: &Job) -> Result<Option<JobResult>> {
+pub fn get_job_result(conn: &Connection, job: &AssignedJob) -> Result<Option<JobResult>> {
     let mut stmt =
-        conn.prepare("SELECT id,status,stdout,stderr FROM job_results WHERE job_id = ?1")?;
+        conn.prepare("SELECT id,status,stdout,stderr FROM job_results WHERE run_id = ?1")?;
     let job_result = stmt
         .query_map(params![job.id], |row| {
             Ok(JobResult {
                 id: row.get(0)?,
-                job_id: job.id,
+                run_id: job.id,
                 status: row.get(1)?,
                 stdout: row.get(2)?,
                 stderr: row.get(3)?,
@@ -162,3 +361,56 @@ pub fn get_job_result(conn: &Connection, job: &Job) -> Result<Option<JobResult>>
         .transpose()?;
     Ok(job_result)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::schema::JobBuilder;
+
+    /// Two workers racing `dequeue_job` against the same two queued runs
+    /// must walk away with one distinct run each, never the same row
+    /// twice and never an extra `None`.
+    #[test]
+    fn dequeue_job_is_race_free() {
+        let path = std::env::temp_dir().join(format!("rat-test-dequeue-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let mut conn = Connection::open(&path).unwrap();
+            configure_connection(&conn).unwrap();
+            create_table(&conn).unwrap();
+
+            let run_at = Utc::now() - chrono::Duration::seconds(1);
+            for _ in 0..2 {
+                let meta = JobBuilder::new().script("true").run_at(run_at).build();
+                let meta_id = insert_meta(&mut conn, &meta).unwrap();
+                insert_run(&mut conn, meta_id, run_at).unwrap();
+            }
+        }
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || {
+                    let conn = Connection::open(&path).unwrap();
+                    configure_connection(&conn).unwrap();
+                    dequeue_job(&conn).unwrap()
+                })
+            })
+            .collect();
+
+        let mut ids: Vec<i64> = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .map(|job| *job.expect("expected a job to be dequeued").id)
+            .collect();
+        ids.sort();
+
+        assert_eq!(ids, vec![1, 2]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
@@ -1,53 +1,157 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 use rusqlite::Connection;
 
-use crate::schema::{Job, JobResult, JobState, ID};
+use crate::schema::{AssignedJob, JobMeta, JobResult, JobState, Schedule, ID};
 
 pub mod commands;
 mod db;
 pub mod schema;
+pub mod serve;
 
 pub type Result<T> = anyhow::Result<T>;
 
+/// How a [`JobManager`] should obtain its `rusqlite::Connection`, mirroring
+/// the Fresh/Existing split used by the external db layer this project
+/// takes its schema conventions from.
+pub enum ConnectionOptions {
+    /// Open `path` as a fresh connection. `disable_logging` skips the
+    /// WAL/busy-timeout setup, which is useful for throwaway connections
+    /// such as an in-memory `:memory:` database in tests, where WAL mode
+    /// isn't supported and there's no other process to contend with.
+    Fresh { path: PathBuf, disable_logging: bool },
+    /// Reuse a connection the caller already opened, e.g. so multiple
+    /// `JobManager`s in the same process (or a test harness) can share
+    /// one handle.
+    Existing(Connection),
+}
+
+/// Caches [`AssignedJob`] rows read via [`JobManager::get_job`] (and the
+/// runs handed out by `dequeue`), keyed by run id, so repeated `get_job`
+/// lookups of the same run (e.g. from `get_job_mut`) hit this instead of
+/// SQLite as long as this `JobManager` hasn't written to the row since.
+/// Writes made through other connections are not seen. `List` and `Log`
+/// do not go through this cache: `get_latest_run`, `get_runs` and
+/// `get_meta` always query SQLite. Nor does `Run`'s poll loop: `dequeue`
+/// claims the next queued run with an atomic `UPDATE ... RETURNING` that
+/// hits SQLite every tick. Entries are invalidated (not updated in place)
+/// by every write path of this `JobManager`, so the next `get_job` after
+/// such a write re-reads the row from SQLite.
+#[derive(Default)]
+struct JobCache {
+    jobs: HashMap<i64, AssignedJob>,
+}
+
+impl JobCache {
+    fn contains(&self, id: ID) -> bool {
+        self.jobs.contains_key(&*id)
+    }
+
+    fn get(&self, id: ID) -> Option<&AssignedJob> {
+        self.jobs.get(&*id)
+    }
+
+    fn insert(&mut self, job: AssignedJob) {
+        self.jobs.insert(*job.id, job);
+    }
+
+    fn invalidate(&mut self, id: ID) {
+        self.jobs.remove(&*id);
+    }
+
+    /// Drops every cached run belonging to `meta_id`, used when its
+    /// `JobMeta` (and all its runs) are deleted.
+    fn invalidate_meta(&mut self, meta_id: ID) {
+        self.jobs.retain(|_, job| job.meta_id != meta_id);
+    }
+}
+
 pub struct JobManager {
     conn: Connection,
+    cache: RefCell<JobCache>,
 }
 
 impl JobManager {
     pub fn new<P: AsRef<Path>>(data_home: P) -> Result<Self> {
-        let data_home = data_home.as_ref();
-        let db_path = data_home.join("rat.db");
-        let conn = Connection::open(db_path)?;
+        let db_path = data_home.as_ref().join("rat.db");
+        Self::with_options(ConnectionOptions::Fresh {
+            path: db_path,
+            disable_logging: false,
+        })
+    }
+
+    pub fn with_options(options: ConnectionOptions) -> Result<Self> {
+        let conn = match options {
+            ConnectionOptions::Fresh {
+                path,
+                disable_logging,
+            } => {
+                let conn = Connection::open(path)?;
+                if !disable_logging {
+                    db::configure_connection(&conn).context("Failed to configure connection")?;
+                }
+                conn
+            }
+            ConnectionOptions::Existing(conn) => conn,
+        };
         let _ = db::create_table(&conn).context("Failed to create table")?;
 
-        Ok(JobManager { conn })
+        Ok(JobManager {
+            conn,
+            cache: RefCell::new(JobCache::default()),
+        })
     }
 
     pub fn dequeue(&mut self) -> Result<Option<JobGuard>> {
         let Some(job) = db::dequeue_job(&self.conn)? else {
             return Ok(None);
         };
-        let mut guard = JobGuard::new(job, self)?;
-        guard.set_state(JobState::Dequeued, None)?;
+        self.cache.borrow_mut().insert(job.clone());
+        let guard = JobGuard::new(job, self)?;
 
         Ok(Some(guard))
     }
 
-    pub fn enqueue(&mut self, mut job: Job) -> Result<Job> {
-        let job_id = db::insert_job(&mut self.conn, &job)?;
-        job.id = job_id.into();
-        Ok(job)
+    /// Registers a new job definition and schedules its first run at
+    /// `first_run_at`.
+    pub fn enqueue(&mut self, mut meta: JobMeta, first_run_at: DateTime<Utc>) -> Result<JobMeta> {
+        let meta_id = db::insert_meta(&mut self.conn, &meta)?;
+        meta.id = meta_id;
+
+        db::insert_run(&mut self.conn, meta_id, first_run_at)?;
+
+        Ok(meta)
     }
 
-    pub fn get_job(&self, job_id: ID) -> Result<Option<Job>> {
-        db::select_job(&self.conn, job_id)
+    /// Schedules another run for a recurring job's meta, called by `Run`
+    /// once a run of it has finished.
+    pub fn enqueue_next_run(&mut self, meta_id: ID, run_at: DateTime<Utc>) -> Result<()> {
+        db::insert_run(&mut self.conn, meta_id, run_at)?;
+        Ok(())
     }
 
-    pub fn get_job_mut(&mut self, job_id: ID) -> Result<Option<JobGuard>> {
-        let Some(job) = db::select_job(&self.conn, job_id)? else {
+    pub fn get_job(&self, run_id: ID) -> Result<Option<AssignedJob>> {
+        if self.cache.borrow().contains(run_id) {
+            log::debug!("cache hit for job:{}", run_id);
+        }
+        if let Some(job) = self.cache.borrow().get(run_id) {
+            return Ok(Some(job.clone()));
+        }
+
+        let job = db::select_job(&self.conn, run_id)?;
+        if let Some(job) = &job {
+            self.cache.borrow_mut().insert(job.clone());
+        }
+        Ok(job)
+    }
+
+    pub fn get_job_mut(&mut self, run_id: ID) -> Result<Option<JobGuard>> {
+        let Some(job) = self.get_job(run_id)? else {
             return Ok(None);
         };
 
@@ -55,21 +159,40 @@ impl JobManager {
         Ok(Some(guard))
     }
 
-    pub fn delete(&mut self, job: &Job) -> Result<()> {
-        if job.state == JobState::Running {
-            return Err(anyhow::anyhow!(
-                "cannot delete a job #{} that is currently running",
-                job.id
-            ));
-        }
-        db::delete_job(&mut self.conn, job)
+    pub fn get_meta(&self, meta_id: ID) -> Result<Option<JobMeta>> {
+        db::select_meta(&self.conn, meta_id)
+    }
+
+    pub fn get_all_metas(&self) -> Result<Vec<JobMeta>> {
+        db::select_all_metas(&self.conn)
     }
 
-    pub fn get_all_jobs(&self) -> Result<Vec<Job>> {
-        db::select_all_jobs(&self.conn)
+    /// The latest scheduled run for a job definition, i.e. what `List`
+    /// shows alongside each `JobMeta`.
+    pub fn get_latest_run(&self, meta_id: ID) -> Result<Option<AssignedJob>> {
+        db::select_latest_run_for_meta(&self.conn, meta_id)
     }
 
-    pub fn get_result(&self, job: &Job) -> Result<Option<JobResult>> {
+    /// The full run history for a job definition, i.e. what `Log` shows.
+    pub fn get_runs(&self, meta_id: ID) -> Result<Vec<AssignedJob>> {
+        db::select_runs_for_meta(&self.conn, meta_id)
+    }
+
+    pub fn delete(&mut self, meta_id: ID) -> Result<()> {
+        if let Some(latest) = db::select_latest_run_for_meta(&self.conn, meta_id)? {
+            if latest.state == JobState::Running {
+                return Err(anyhow::anyhow!(
+                    "cannot delete job {} that is currently running",
+                    meta_id
+                ));
+            }
+        }
+        db::delete_meta(&mut self.conn, meta_id)?;
+        self.cache.borrow_mut().invalidate_meta(meta_id);
+        Ok(())
+    }
+
+    pub fn get_result(&self, job: &AssignedJob) -> Result<Option<JobResult>> {
         db::get_job_result(&self.conn, job)
     }
 }
@@ -111,23 +234,32 @@ fn bytes_to_path(buf: &[u8]) -> PathBuf {
 }
 
 pub struct JobGuard<'m> {
-    job: Job,
+    job: AssignedJob,
     manager: &'m mut JobManager,
 
+    /// The run's state as of the last DB write this guard made, so `Drop`
+    /// knows which state to require in its conditional cleanup `UPDATE`
+    /// instead of assuming the run is still `Dequeued`.
+    state: JobState,
     done: bool,
 }
 
 impl<'m> JobGuard<'m> {
-    fn new(job: Job, manager: &'m mut JobManager) -> Result<Self> {
+    fn new(job: AssignedJob, manager: &'m mut JobManager) -> Result<Self> {
+        let state = job.state;
         Ok(JobGuard {
             job,
             manager,
+            state,
             done: false,
         })
     }
 
     pub fn mark_running(&mut self) -> Result<()> {
-        self.set_state(JobState::Running, None)
+        db::mark_running(&mut self.manager.conn, &self.job)?;
+        self.manager.cache.borrow_mut().invalidate(self.job.id);
+        self.state = JobState::Running;
+        Ok(())
     }
 
     pub fn cancel(&mut self) -> Result<()> {
@@ -135,22 +267,47 @@ impl<'m> JobGuard<'m> {
         self.set_state(JobState::Canceled, None)
     }
 
+    /// Puts the run back in the queue for another attempt at `run_at`,
+    /// bumping its attempt count and recording why the previous attempt
+    /// failed.
+    pub fn retry(&mut self, run_at: DateTime<Utc>, failure: schema::FailureKind) -> Result<()> {
+        db::retry_job(&mut self.manager.conn, &self.job, run_at, failure)?;
+        self.manager.cache.borrow_mut().invalidate(self.job.id);
+        self.done = true;
+        Ok(())
+    }
+
     fn set_state(&mut self, state: JobState, cond_state: Option<JobState>) -> Result<()> {
-        db::update_job_state(&mut self.manager.conn, &self.job, state, cond_state)
+        db::update_job_state(&mut self.manager.conn, &self.job, state, cond_state)?;
+        self.manager.cache.borrow_mut().invalidate(self.job.id);
+        self.state = state;
+        Ok(())
     }
 
     pub fn save_job_result(&mut self, job_result: JobResult) -> Result<JobResult> {
         let job_result_id = db::insert_job_result(&mut self.manager.conn, &job_result)?;
+        self.manager.cache.borrow_mut().invalidate(self.job.id);
         self.done = true;
         Ok(JobResult {
             id: job_result_id.into(),
             ..job_result
         })
     }
+
+    /// If this run's job is recurring, schedules the next occurrence
+    /// `interval` after now. Call once a run has reached a terminal
+    /// state (`Done`).
+    pub fn schedule_next_if_recurring(&mut self) -> Result<()> {
+        if let Schedule::Recurring(interval) = self.job.schedule {
+            let next_run_at = Utc::now() + chrono::Duration::from_std(interval).unwrap_or_default();
+            self.manager.enqueue_next_run(self.job.meta_id, next_run_at)?;
+        }
+        Ok(())
+    }
 }
 
 impl Deref for JobGuard<'_> {
-    type Target = Job;
+    type Target = AssignedJob;
 
     fn deref(&self) -> &Self::Target {
         &self.job
@@ -160,7 +317,13 @@ impl Deref for JobGuard<'_> {
 impl Drop for JobGuard<'_> {
     fn drop(&mut self) {
         if !self.done {
-            let _ = self.set_state(JobState::Queued, Some(JobState::Dequeued));
+            // Requeue from whatever state this guard last wrote (`Dequeued`
+            // if it never got as far as `mark_running`, `Running` if it
+            // did), not a hardcoded `Dequeued`, so a guard that errors out
+            // after marking the run `Running` still gets requeued instead
+            // of being orphaned forever in `Running`.
+            let cond_state = self.state;
+            let _ = self.set_state(JobState::Queued, Some(cond_state));
         }
     }
 }
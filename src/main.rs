@@ -1,5 +1,5 @@
 use clap::{Parser, Subcommand};
-use rat::{commands, JobManager, Result};
+use rat::{commands, serve, JobManager, Result};
 use xdg::BaseDirectories;
 
 fn setup_directories(base: &BaseDirectories) -> Result<()> {
@@ -32,6 +32,7 @@ fn do_main() -> Result<()> {
         Commands::Delete(delete) => delete.run(job_manager)?,
         Commands::Run(run) => run.run(job_manager)?,
         Commands::Log(log) => log.run(job_manager)?,
+        Commands::Serve(serve) => serve.run(job_manager)?,
     };
     Ok(())
 }
@@ -50,6 +51,7 @@ enum Commands {
     Delete(commands::Delete),
     Run(commands::Run),
     Log(commands::Log),
+    Serve(serve::Serve),
 }
 
 fn main() {
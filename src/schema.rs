@@ -3,6 +3,7 @@ use std::fmt::{Display, Formatter};
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::path::PathBuf;
+use std::time::Duration;
 use std::{env, fmt};
 
 use chrono::{DateTime, Utc};
@@ -52,79 +53,152 @@ impl ToSql for JobState {
     }
 }
 
+/// Why a job attempt failed, kept around on the run row so `rat log` can
+/// show why a retry happened.
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FailureKind {
+    /// The child process could not even be spawned.
+    Spawn = 0,
+    /// The script ran and exited with a nonzero status.
+    NonZeroExit = 1,
+    /// The script was killed by a signal.
+    Signal = 2,
+    /// The script ran past its `timeout` and was killed.
+    Timeout = 3,
+}
+
+impl Display for FailureKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            FailureKind::Spawn => write!(f, "spawn failure"),
+            FailureKind::NonZeroExit => write!(f, "nonzero exit"),
+            FailureKind::Signal => write!(f, "killed by signal"),
+            FailureKind::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+impl FromSql for FailureKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(FailureKind::Spawn),
+            1 => Ok(FailureKind::NonZeroExit),
+            2 => Ok(FailureKind::Signal),
+            3 => Ok(FailureKind::Timeout),
+            _ => Err(rusqlite::types::FromSqlError::InvalidType),
+        }
+    }
+}
+
+impl ToSql for FailureKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        use rusqlite::types::Value::Integer;
+        let kind = *self as i64;
+        Ok(ToSqlOutput::Owned(Integer(kind)))
+    }
+}
+
+/// When a [`JobMeta`] should fire. `Once` carries the single instant it
+/// runs at; `Recurring` carries the interval between runs, with each new
+/// [`AssignedJob`] enqueued `interval` after the previous one finished.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Schedule {
+    Once(DateTime<Utc>),
+    Recurring(Duration),
+}
+
 pub struct Missing;
 pub struct Present;
 
-pub struct JobBuilder<StateSet, ScriptSet, RunAtSet> {
+/// Builds a [`JobMeta`]. `script` and a `Schedule` (set via `run_at` for
+/// a one-shot job or `recurring` for a repeating one) are mandatory;
+/// everything else has a sensible default.
+pub struct JobBuilder<ScriptSet, ScheduleSet> {
     name: Option<String>,
-    state: Option<JobState>,
     script: Option<String>,
-    run_at: Option<DateTime<Utc>>,
+    schedule: Option<Schedule>,
     cwd: Option<PathBuf>,
+    max_retries: Option<i32>,
+    retry_delay: Option<Duration>,
+    timeout: Option<Duration>,
 
-    _marker: PhantomData<(StateSet, ScriptSet, RunAtSet)>,
+    _marker: PhantomData<(ScriptSet, ScheduleSet)>,
 }
 
-impl JobBuilder<Missing, Missing, Missing> {
+impl JobBuilder<Missing, Missing> {
     pub fn new() -> Self {
         Self {
             name: None,
-            state: None,
             script: None,
-            run_at: None,
+            schedule: None,
             cwd: None,
+            max_retries: None,
+            retry_delay: None,
+            timeout: None,
             _marker: PhantomData,
         }
     }
 }
 
-impl<ScriptSet, RunAtSet> JobBuilder<Missing, ScriptSet, RunAtSet> {
-    pub fn state(self, state: JobState) -> JobBuilder<Present, ScriptSet, RunAtSet> {
+impl<ScheduleSet> JobBuilder<Missing, ScheduleSet> {
+    pub fn script(self, script: impl Into<String>) -> JobBuilder<Present, ScheduleSet> {
         JobBuilder {
             name: self.name,
-            state: Some(state),
-            script: self.script,
-            run_at: self.run_at,
+            script: Some(script.into()),
+            schedule: self.schedule,
             cwd: self.cwd,
+            max_retries: self.max_retries,
+            retry_delay: self.retry_delay,
+            timeout: self.timeout,
             _marker: PhantomData,
         }
     }
 }
 
-impl<StateSet, RunAtSet> JobBuilder<StateSet, Missing, RunAtSet> {
-    pub fn script(self, script: impl Into<String>) -> JobBuilder<StateSet, Present, RunAtSet> {
+impl<ScriptSet> JobBuilder<ScriptSet, Missing> {
+    /// Runs the job once, at `run_at`.
+    pub fn run_at(self, run_at: DateTime<Utc>) -> JobBuilder<ScriptSet, Present> {
         JobBuilder {
             name: self.name,
-            state: self.state,
-            script: Some(script.into()),
-            run_at: self.run_at,
+            script: self.script,
+            schedule: Some(Schedule::Once(run_at)),
             cwd: self.cwd,
+            max_retries: self.max_retries,
+            retry_delay: self.retry_delay,
+            timeout: self.timeout,
             _marker: PhantomData,
         }
     }
-}
 
-impl<StateSet, ScriptSet> JobBuilder<StateSet, ScriptSet, Missing> {
-    pub fn run_at(self, run_at: DateTime<Utc>) -> JobBuilder<StateSet, ScriptSet, Present> {
+    /// Runs the job on a recurring basis, with each next run enqueued
+    /// `interval` after the previous one finishes. The first run fires
+    /// whenever the caller schedules it (see `JobManager::enqueue`'s
+    /// `first_run_at`), not immediately.
+    pub fn recurring(self, interval: Duration) -> JobBuilder<ScriptSet, Present> {
         JobBuilder {
             name: self.name,
-            state: self.state,
             script: self.script,
-            run_at: Some(run_at),
+            schedule: Some(Schedule::Recurring(interval)),
             cwd: self.cwd,
+            max_retries: self.max_retries,
+            retry_delay: self.retry_delay,
+            timeout: self.timeout,
             _marker: PhantomData,
         }
     }
 }
 
-impl<StateSet, ScriptSet, RunAtSet> JobBuilder<StateSet, ScriptSet, RunAtSet> {
+impl<ScriptSet, ScheduleSet> JobBuilder<ScriptSet, ScheduleSet> {
     pub fn name(self, name: impl Into<String>) -> Self {
         JobBuilder {
             name: Some(name.into()),
-            state: self.state,
             script: self.script,
-            run_at: self.run_at,
+            schedule: self.schedule,
             cwd: self.cwd,
+            max_retries: self.max_retries,
+            retry_delay: self.retry_delay,
+            timeout: self.timeout,
             _marker: PhantomData,
         }
     }
@@ -132,26 +206,75 @@ impl<StateSet, ScriptSet, RunAtSet> JobBuilder<StateSet, ScriptSet, RunAtSet> {
     pub fn cwd(self, cwd: impl Into<PathBuf>) -> Self {
         JobBuilder {
             name: self.name,
-            state: self.state,
             script: self.script,
-            run_at: self.run_at,
+            schedule: self.schedule,
             cwd: Some(cwd.into()),
+            max_retries: self.max_retries,
+            retry_delay: self.retry_delay,
+            timeout: self.timeout,
+            _marker: PhantomData,
+        }
+    }
+
+    /// How many times a failed attempt should be retried before a run is
+    /// finally marked `Done`. Defaults to `0` (no retries).
+    pub fn max_retries(self, max_retries: i32) -> Self {
+        JobBuilder {
+            name: self.name,
+            script: self.script,
+            schedule: self.schedule,
+            cwd: self.cwd,
+            max_retries: Some(max_retries),
+            retry_delay: self.retry_delay,
+            timeout: self.timeout,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Base delay before the first retry; later retries back off
+    /// exponentially from this value. Defaults to zero.
+    pub fn retry_delay(self, retry_delay: Duration) -> Self {
+        JobBuilder {
+            name: self.name,
+            script: self.script,
+            schedule: self.schedule,
+            cwd: self.cwd,
+            max_retries: self.max_retries,
+            retry_delay: Some(retry_delay),
+            timeout: self.timeout,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Kills the script if it hasn't finished within `timeout`. Defaults
+    /// to no timeout.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        JobBuilder {
+            name: self.name,
+            script: self.script,
+            schedule: self.schedule,
+            cwd: self.cwd,
+            max_retries: self.max_retries,
+            retry_delay: self.retry_delay,
+            timeout: Some(timeout),
             _marker: PhantomData,
         }
     }
 }
 
-impl JobBuilder<Present, Present, Present> {
-    pub fn build(self) -> Job {
-        let cwd = env::current_dir().unwrap();
+impl JobBuilder<Present, Present> {
+    pub fn build(self) -> JobMeta {
+        let cwd = self.cwd.unwrap_or_else(|| env::current_dir().unwrap());
 
-        Job {
+        JobMeta {
             id: 0.into(),
             name: self.name,
-            state: self.state.unwrap(),
             script: self.script.unwrap(),
-            run_at: self.run_at.unwrap(),
             cwd,
+            schedule: self.schedule.unwrap(),
+            max_retries: self.max_retries.unwrap_or(0),
+            retry_delay: self.retry_delay.unwrap_or(Duration::ZERO),
+            timeout: self.timeout,
         }
     }
 }
@@ -191,23 +314,53 @@ impl ToSql for ID {
     }
 }
 
+/// A job definition: what to run, where, and on what schedule. One
+/// `JobMeta` can spawn many [`AssignedJob`] runs over time when it's
+/// `Recurring`.
 #[derive(Debug, Eq, PartialEq)]
-pub struct Job {
+pub struct JobMeta {
     pub id: ID,
     pub name: Option<String>,
-    pub state: JobState,
     pub script: String,
-    pub run_at: DateTime<Utc>,
     pub cwd: PathBuf,
+    pub schedule: Schedule,
+    pub max_retries: i32,
+    pub retry_delay: Duration,
+    pub timeout: Option<Duration>,
+}
+
+/// A single scheduled execution of a [`JobMeta`]. This is what
+/// `JobManager::dequeue` hands out and what carries the mutable
+/// execution state (`state`, `attempts`, `last_failure`); the
+/// immutable definition (`script`, `cwd`, retry policy) is copied in
+/// from the owning `JobMeta` at read time so callers don't need a
+/// second round-trip to run it.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AssignedJob {
+    pub id: ID,
+    pub meta_id: ID,
+    pub name: Option<String>,
+    pub script: String,
+    pub cwd: PathBuf,
+    pub schedule: Schedule,
+    pub max_retries: i32,
+    pub retry_delay: Duration,
+    pub timeout: Option<Duration>,
+
+    pub state: JobState,
+    pub run_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub attempts: i32,
+    pub last_failure: Option<FailureKind>,
 }
 
-impl PartialOrd<Self> for Job {
+impl PartialOrd<Self> for AssignedJob {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for Job {
+impl Ord for AssignedJob {
     fn cmp(&self, other: &Self) -> Ordering {
         self.run_at.cmp(&other.run_at)
     }
@@ -216,7 +369,7 @@ impl Ord for Job {
 #[derive(Debug)]
 pub struct JobResult {
     pub id: ID,
-    pub job_id: ID,
+    pub run_id: ID,
     pub status: Option<i16>,
 
     pub stdout: String,
@@ -224,11 +377,11 @@ pub struct JobResult {
 }
 
 impl JobResult {
-    pub fn new(job_id: impl Into<ID>) -> Self {
-        let job_id = job_id.into();
+    pub fn new(run_id: impl Into<ID>) -> Self {
+        let run_id = run_id.into();
         Self {
             id: 0.into(),
-            job_id,
+            run_id,
             status: None,
             stdout: String::new(),
             stderr: String::new(),
@@ -0,0 +1,319 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::schema::{JobBuilder, JobState, Schedule, ID};
+use crate::JobManager;
+
+/// Starts an HTTP server wrapping a [`JobManager`] so jobs can be
+/// enqueued, listed, and inspected remotely instead of only through the
+/// local CLI.
+#[derive(Args, Debug)]
+pub struct Serve {
+    /// Address to listen on. There is no authentication: whoever can
+    /// reach this address can enqueue arbitrary scripts that run as this
+    /// process's user. The default only binds to loopback; only pass a
+    /// non-loopback address if the network reaching it is trusted.
+    #[clap(short, long, default_value = "127.0.0.1:8080")]
+    pub addr: String,
+}
+
+impl Serve {
+    pub fn run(&self, job_manager: JobManager) -> anyhow::Result<()> {
+        let server = Server::http(&self.addr)
+            .map_err(|err| anyhow::anyhow!("failed to bind {}: {}", self.addr, err))?;
+        log::info!("listening on {}", self.addr);
+
+        // `rusqlite::Connection` isn't `Sync`, so the manager can't be
+        // shared across request threads directly; a mutex serializes DB
+        // access the same way a single `rat run` worker would.
+        let job_manager = Arc::new(Mutex::new(job_manager));
+
+        for request in server.incoming_requests() {
+            let job_manager = Arc::clone(&job_manager);
+            thread::spawn(move || {
+                if let Err(err) = handle_request(&job_manager, request) {
+                    log::error!("request failed: {:?}", err);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct JobSummary {
+    id: i64,
+    name: Option<String>,
+    script: String,
+    schedule: String,
+    state: Option<String>,
+    run_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct RunSummary {
+    id: i64,
+    state: String,
+    run_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    attempts: i32,
+    last_failure: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LogResponse {
+    meta_id: i64,
+    runs: Vec<RunSummary>,
+    stdout: Option<String>,
+    stderr: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EnqueueRequest {
+    name: Option<String>,
+    script: String,
+    cwd: Option<PathBuf>,
+    run_at: DateTime<Utc>,
+    /// Humantime-parsed interval, e.g. `"10m"`, for a recurring job.
+    every: Option<String>,
+    max_retries: Option<i32>,
+    retry_delay: Option<String>,
+    timeout: Option<String>,
+}
+
+#[derive(Serialize)]
+struct IdResponse {
+    id: i64,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+fn handle_request(job_manager: &Mutex<JobManager>, mut request: Request) -> anyhow::Result<()> {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let segments: Vec<&str> = url
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let result = match (&method, segments.as_slice()) {
+        (Method::Get, ["jobs"]) => list_jobs(job_manager),
+        (Method::Post, ["jobs"]) => {
+            let mut body = String::new();
+            std::io::Read::read_to_string(request.as_reader(), &mut body)?;
+            enqueue_job(job_manager, &body)
+        }
+        (Method::Get, ["jobs", meta_id]) => get_log(job_manager, meta_id),
+        (Method::Post, ["jobs", meta_id, "cancel"]) => cancel_job(job_manager, meta_id),
+        (Method::Delete, ["jobs", meta_id]) => delete_job(job_manager, meta_id),
+        _ => Ok(json_response(
+            404,
+            &ErrorBody {
+                error: "not found".to_string(),
+            },
+        )),
+    };
+
+    let response = result.unwrap_or_else(|err| {
+        json_response(
+            500,
+            &ErrorBody {
+                error: err.to_string(),
+            },
+        )
+    });
+
+    request.respond(response).context("failed to send response")
+}
+
+fn list_jobs(job_manager: &Mutex<JobManager>) -> anyhow::Result<Response<Cursor<Vec<u8>>>> {
+    let job_manager = job_manager.lock().unwrap();
+    let metas = job_manager.get_all_metas()?;
+
+    let mut jobs = Vec::with_capacity(metas.len());
+    for meta in metas {
+        let latest = job_manager.get_latest_run(meta.id)?;
+        let schedule = match meta.schedule {
+            Schedule::Once(_) => "once".to_string(),
+            Schedule::Recurring(interval) => {
+                format!("every {}", humantime::format_duration(interval))
+            }
+        };
+
+        jobs.push(JobSummary {
+            id: *meta.id,
+            name: meta.name,
+            script: meta.script,
+            schedule,
+            state: latest.as_ref().map(|run| run.state.to_string()),
+            run_at: latest.map(|run| run.run_at),
+        });
+    }
+
+    Ok(json_response(200, &jobs))
+}
+
+fn enqueue_job(
+    job_manager: &Mutex<JobManager>,
+    body: &str,
+) -> anyhow::Result<Response<Cursor<Vec<u8>>>> {
+    let req: EnqueueRequest = match serde_json::from_str(body) {
+        Ok(req) => req,
+        Err(err) => {
+            return Ok(json_response(
+                400,
+                &ErrorBody {
+                    error: err.to_string(),
+                },
+            ))
+        }
+    };
+
+    let cwd = req
+        .cwd
+        .unwrap_or(std::env::current_dir().context("failed to get current directory")?);
+
+    let mut job_builder = JobBuilder::new().script(req.script).cwd(cwd);
+    if let Some(name) = req.name {
+        job_builder = job_builder.name(name);
+    }
+    if let Some(max_retries) = req.max_retries {
+        job_builder = job_builder.max_retries(max_retries);
+    }
+    if let Some(retry_delay) = req.retry_delay {
+        let retry_delay = humantime::parse_duration(&retry_delay)
+            .map_err(|err| anyhow::anyhow!("invalid retry_delay: {}", err))?;
+        job_builder = job_builder.retry_delay(retry_delay);
+    }
+    if let Some(timeout) = req.timeout {
+        let timeout = humantime::parse_duration(&timeout)
+            .map_err(|err| anyhow::anyhow!("invalid timeout: {}", err))?;
+        job_builder = job_builder.timeout(timeout);
+    }
+
+    let meta = if let Some(every) = req.every {
+        let every = humantime::parse_duration(&every)
+            .map_err(|err| anyhow::anyhow!("invalid every: {}", err))?;
+        job_builder.recurring(every).build()
+    } else {
+        job_builder.run_at(req.run_at).build()
+    };
+
+    let mut job_manager = job_manager.lock().unwrap();
+    let meta = job_manager.enqueue(meta, req.run_at)?;
+
+    Ok(json_response(201, &IdResponse { id: *meta.id }))
+}
+
+fn get_log(
+    job_manager: &Mutex<JobManager>,
+    meta_id: &str,
+) -> anyhow::Result<Response<Cursor<Vec<u8>>>> {
+    let Ok(meta_id) = meta_id.parse::<i64>() else {
+        return Ok(not_found());
+    };
+    let meta_id: ID = meta_id.into();
+
+    let job_manager = job_manager.lock().unwrap();
+    if job_manager.get_meta(meta_id)?.is_none() {
+        return Ok(not_found());
+    }
+
+    let runs = job_manager.get_runs(meta_id)?;
+    let latest_done = runs.iter().rev().find(|run| run.state == JobState::Done);
+    let result = latest_done
+        .map(|run| job_manager.get_result(run))
+        .transpose()?
+        .flatten();
+
+    let response = LogResponse {
+        meta_id: *meta_id,
+        runs: runs
+            .iter()
+            .map(|run| RunSummary {
+                id: *run.id,
+                state: run.state.to_string(),
+                run_at: run.run_at,
+                started_at: run.started_at,
+                attempts: run.attempts,
+                last_failure: run.last_failure.map(|f| f.to_string()),
+            })
+            .collect(),
+        stdout: result.as_ref().map(|r| r.stdout.clone()),
+        stderr: result.as_ref().map(|r| r.stderr.clone()),
+    };
+
+    Ok(json_response(200, &response))
+}
+
+/// Cancels the latest run of a job definition, the way `meta_id`-scoped
+/// routes cancel/delete at the `JobMeta` level rather than a specific
+/// run id.
+fn cancel_job(
+    job_manager: &Mutex<JobManager>,
+    meta_id: &str,
+) -> anyhow::Result<Response<Cursor<Vec<u8>>>> {
+    let Ok(meta_id) = meta_id.parse::<i64>() else {
+        return Ok(not_found());
+    };
+    let meta_id: ID = meta_id.into();
+
+    let mut job_manager = job_manager.lock().unwrap();
+    let Some(latest) = job_manager.get_latest_run(meta_id)? else {
+        return Ok(not_found());
+    };
+    let Some(mut guard) = job_manager.get_job_mut(latest.id)? else {
+        return Ok(not_found());
+    };
+    guard.cancel()?;
+
+    Ok(json_response(200, &IdResponse { id: *meta_id }))
+}
+
+fn delete_job(
+    job_manager: &Mutex<JobManager>,
+    meta_id: &str,
+) -> anyhow::Result<Response<Cursor<Vec<u8>>>> {
+    let Ok(meta_id) = meta_id.parse::<i64>() else {
+        return Ok(not_found());
+    };
+    let meta_id: ID = meta_id.into();
+
+    let mut job_manager = job_manager.lock().unwrap();
+    if job_manager.get_meta(meta_id)?.is_none() {
+        return Ok(not_found());
+    }
+    job_manager.delete(meta_id)?;
+
+    Ok(json_response(200, &IdResponse { id: *meta_id }))
+}
+
+fn not_found() -> Response<Cursor<Vec<u8>>> {
+    json_response(
+        404,
+        &ErrorBody {
+            error: "job not found".to_string(),
+        },
+    )
+}
+
+fn json_response<T: Serialize>(status: u16, body: &T) -> Response<Cursor<Vec<u8>>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    let header =
+        Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header)
+}